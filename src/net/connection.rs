@@ -1,15 +1,19 @@
 use super::event_loop::EventLoop;
+use super::selector::{Interest, Readiness};
 use log::{debug, warn};
 use nix::errno::Errno;
-use nix::sys::epoll::EpollFlags;
-use nix::sys::socket::{accept4, connect, setsockopt, sockopt};
-use nix::sys::socket::{getpeername, shutdown, socket, Shutdown};
+use nix::sys::socket::{accept4, connect, getsockopt, setsockopt, sockopt};
+use nix::sys::socket::{getpeername, getsockname, shutdown, socket, Shutdown};
 use nix::sys::socket::{AddressFamily, InetAddr, SockAddr, SockFlag, SockProtocol, SockType};
-use nix::unistd::{read, write};
+use nix::sys::uio::{writev, IoVec};
+use nix::unistd::{read, unlink};
+use std::collections::VecDeque;
 use std::net::{SocketAddr, TcpListener};
-use std::os::unix::prelude::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::prelude::{AsRawFd, FromRawFd};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub type ConnRef = Arc<Mutex<Connection>>;
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -19,33 +23,78 @@ pub enum State {
     Writing,
     Finished,
     Closed,
+    /// Data connection broke mid-transfer; held open for the client to
+    /// reconnect and resume within the grace window.
+    Reconnecting,
 }
 
-const READABLE: u8 = 0b0001;
-const WRITABLE: u8 = 0b0010;
+/// How long a broken transfer stays resumable (see `TransferProgress::is_resumable`).
+/// The idle-session reaper does not consult it.
+pub const RESYNC_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
-trait EventSet {
-    fn is_readable(&self) -> bool;
-    fn is_writeable(&self) -> bool;
-    fn is_close(&self) -> bool;
-    fn is_error(&self) -> bool;
-    fn is_hup(&self) -> bool;
+/// `IOV_MAX`/`UIO_MAXIOV` on most platforms; caps chunks per `writev` call.
+const MAX_IOV: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Upload,
+    Download,
 }
-impl EventSet for EpollFlags {
-    fn is_readable(&self) -> bool {
-        (*self & (EpollFlags::EPOLLIN | EpollFlags::EPOLLPRI)).bits() > 0
+
+/// Progress of a single data transfer, kept on the data `Connection` so a
+/// mid-stream error can resume from `offset` instead of restarting.
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    pub path: String,
+    pub offset: u64,
+    pub direction: TransferDirection,
+    interrupted_at: Option<Instant>,
+}
+
+impl TransferProgress {
+    fn mark_interrupted(&mut self) {
+        self.interrupted_at = Some(Instant::now());
     }
-    fn is_writeable(&self) -> bool {
-        (*self & EpollFlags::EPOLLOUT).bits() > 0
+    /// Whether we're still within the grace window for resuming.
+    pub fn is_resumable(&self) -> bool {
+        self.interrupted_at
+            .map_or(true, |t| t.elapsed() < RESYNC_GRACE_PERIOD)
     }
-    fn is_close(&self) -> bool {
-        (*self & EpollFlags::EPOLLHUP).bits() > 0 && !((*self & EpollFlags::EPOLLIN).bits() > 0)
+}
+
+/// Per-connection token-bucket rate limiter.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,     // bytes/sec
+    capacity: f64, // burst size, one second worth of `rate`
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate_bytes_per_sec: u64) -> Self {
+        let rate = rate_bytes_per_sec as f64;
+        TokenBucket {
+            rate,
+            capacity: rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
     }
-    fn is_error(&self) -> bool {
-        (*self & EpollFlags::EPOLLERR).bits() > 0
+
+    fn refill(&mut self) {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.last_refill = Instant::now();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
     }
-    fn is_hup(&self) -> bool {
-        (*self & EpollFlags::EPOLLHUP).bits() > 0
+
+    /// Consume up to `n` bytes worth of tokens and return how many, without blocking.
+    fn acquire(&mut self, n: usize) -> usize {
+        self.refill();
+        // Only whole bytes are taken, so a fractional remainder keeps accruing.
+        let allowed = self.tokens.min(n as f64).floor();
+        self.tokens -= allowed;
+        allowed as usize
     }
 }
 
@@ -53,8 +102,14 @@ impl EventSet for EpollFlags {
 pub struct Connection {
     fd: i32,
     state: State,
-    write_buf: Vec<u8>,
+    // Each `write_buf()` call is queued as its own chunk instead of being
+    // appended to one flat buffer, so `write()` can hand the kernel every
+    // pending chunk in a single `writev` rather than one `write` per call.
+    write_chunks: VecDeque<Vec<u8>>,
+    write_offset: usize, // bytes of write_chunks[0] already sent
     read_buf: Vec<u8>,
+    rate_limiter: Option<TokenBucket>,
+    transfer: Option<TransferProgress>,
 }
 
 impl Connection {
@@ -62,14 +117,68 @@ impl Connection {
         Connection {
             fd,
             state: State::Ready,
-            write_buf: Vec::new(),
+            write_chunks: VecDeque::new(),
+            write_offset: 0,
             read_buf: Vec::new(),
+            rate_limiter: None,
+            transfer: None,
         }
     }
+    /// Cap this connection's outgoing rate; `0` disables throttling.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limiter = if bytes_per_sec == 0 {
+            None
+        } else {
+            Some(TokenBucket::new(bytes_per_sec))
+        };
+    }
     pub fn bind(addr: &str) -> (i32, TcpListener) {
         let listener = TcpListener::bind(addr).unwrap();
         (listener.as_raw_fd(), listener)
     }
+    /// Wrap a listening fd handed down by a socket-activating supervisor
+    /// (systemd, inetd, ...), after checking it's really an AF_INET listener.
+    pub fn from_listen_fd(fd: i32) -> (i32, TcpListener) {
+        let is_inet = matches!(getsockname(fd), Ok(SockAddr::Inet(_)));
+        let is_listening = getsockopt(fd, sockopt::AcceptConn).unwrap_or(false);
+        if !is_inet || !is_listening {
+            panic!(
+                "fd {} from socket activation is not an AF_INET listening socket",
+                fd
+            );
+        }
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        (fd, listener)
+    }
+    /// Listen on a Unix domain socket path instead of a TCP port.
+    pub fn bind_unix(path: &str) -> (i32, UnixListener) {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        (listener.as_raw_fd(), listener)
+    }
+    /// Remove the socket file created by `bind_unix`.
+    pub fn unlink_unix(path: &str) {
+        match unlink(path) {
+            Ok(()) => (),
+            Err(e) => warn!("unlink unix socket {} failed: {}", path, e),
+        }
+    }
+    pub fn connect_unix(path: &str) -> Connection {
+        let sockfd = socket(
+            AddressFamily::Unix,
+            SockType::Stream,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )
+        .unwrap();
+        let sock_addr = SockAddr::new_unix(path).unwrap();
+        match connect(sockfd, &sock_addr) {
+            Ok(()) => debug!("a new unix connection: {}", sockfd),
+            Err(e) => warn!("connect unix failed: {}", e),
+        }
+        return Connection::new(sockfd);
+    }
     pub fn connect(addr: &str) -> Connection {
         let sockfd = socket(
             AddressFamily::Inet,
@@ -94,27 +203,70 @@ impl Connection {
         setsockopt(fd, sockopt::TcpNoDelay, &true).unwrap();
         Connection::new(fd)
     }
+    /// Like `accept`, but for a Unix domain socket listener.
+    pub fn accept_unix(listen_fd: i32) -> Self {
+        let fd = accept4(listen_fd, SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK).unwrap();
+        Connection::new(fd)
+    }
 
     pub fn connected(&self) -> bool {
         self.state != State::Closed
     }
 
-    pub fn dispatch(&mut self, revents: EpollFlags) -> State {
+    pub fn dispatch(&mut self, event_loop: &mut EventLoop, revents: Readiness) -> State {
         self.state = State::Ready;
         if revents.is_readable() {
             self.read();
         }
         if revents.is_writeable() {
-            self.write();
+            self.write(event_loop);
         }
         if revents.is_error() {
-            self.state = State::Closed;
+            self.state = self.broken_state();
         }
         if revents.is_close() {
-            self.state = State::Closed;
+            self.state = self.broken_state();
         }
         return self.state;
     }
+    /// State to fall into on error/hangup: `Reconnecting` if a resumable
+    /// transfer was in flight, otherwise `Closed`.
+    fn broken_state(&mut self) -> State {
+        match self.transfer.as_mut() {
+            Some(t) => {
+                t.mark_interrupted();
+                debug!(
+                    "data connection {} broke mid-transfer at offset {}, holding for resync",
+                    self.fd, t.offset
+                );
+                State::Reconnecting
+            }
+            None => State::Closed,
+        }
+    }
+    /// Begin tracking a resumable transfer on this (data) connection.
+    pub fn begin_transfer(&mut self, path: String, offset: u64, direction: TransferDirection) {
+        self.transfer = Some(TransferProgress {
+            path,
+            offset,
+            direction,
+            interrupted_at: None,
+        });
+    }
+    pub fn transfer_progress(&self) -> Option<&TransferProgress> {
+        self.transfer.as_ref()
+    }
+    /// Record that `n` more bytes of the current transfer have been written
+    /// or read on this connection.
+    pub fn advance_transfer(&mut self, n: u64) {
+        if let Some(t) = self.transfer.as_mut() {
+            t.offset += n;
+        }
+    }
+    /// Stop tracking the transfer; a later error is then a plain close.
+    pub fn end_transfer(&mut self) {
+        self.transfer = None;
+    }
     pub fn get_fd(&self) -> i32 {
         self.fd
     }
@@ -128,14 +280,7 @@ impl Connection {
     }
     pub fn register_read(&mut self, event_loop: &mut EventLoop) {
         self.read_buf.clear();
-        event_loop.reregister(
-            self.fd,
-            EpollFlags::EPOLLHUP
-                | EpollFlags::EPOLLERR
-                | EpollFlags::EPOLLIN
-                | EpollFlags::EPOLLOUT
-                | EpollFlags::EPOLLET,
-        );
+        event_loop.reregister(self.fd, Interest::readable_writable(true));
     }
     pub fn deregister(&self, event_loop: &mut EventLoop) {
         event_loop.deregister(self.fd);
@@ -147,20 +292,103 @@ impl Connection {
             Err(e) => warn!("Shutdown {} occur {} error", self.fd, e),
         }
     }
-    pub fn send(&mut self, buf: &[u8]) {
-        match write(self.fd, buf) {
-            Ok(_) => (),
-            Err(e) => warn!("send data error: {}", e),
-        };
+    /// Queue `buf` for output and try to flush it straight away.
+    pub fn send(&mut self, buf: &[u8], event_loop: &mut EventLoop) {
+        self.write_buf(buf);
+        self.write(event_loop);
     }
+    /// Queue `buf` as a pending output chunk; `write()` puts it on the wire.
     pub fn write_buf(&mut self, buf: &[u8]) {
-
-        // TODO:
+        if !buf.is_empty() {
+            self.write_chunks.push_back(buf.to_vec());
+        }
+    }
+    fn pending_len(&self) -> usize {
+        self.write_chunks.iter().map(Vec::len).sum::<usize>() - self.write_offset
+    }
+    /// Drop the `n` bytes `writev` just reported as sent off the front.
+    fn consume(&mut self, mut n: usize) {
+        while n > 0 {
+            let front_len = match self.write_chunks.front() {
+                Some(c) => c.len() - self.write_offset,
+                None => break,
+            };
+            if n < front_len {
+                self.write_offset += n;
+                break;
+            }
+            n -= front_len;
+            self.write_chunks.pop_front();
+            self.write_offset = 0;
+        }
     }
-    pub fn write(&mut self) {
-        // TODO:
-        // write(self.fd, &data).unwrap();
+    /// Drain as much pending output as the socket will take via `writev`.
+    pub fn write(&mut self, event_loop: &mut EventLoop) {
+        let mut throttled = false;
+        while self.pending_len() > 0 {
+            let mut budget = self.pending_len();
+            if let Some(limiter) = self.rate_limiter.as_mut() {
+                let allowed = limiter.acquire(budget);
+                if allowed == 0 {
+                    // Out of tokens: the socket itself is still writable at
+                    // the OS level (we just chose not to use the window),
+                    // so there's no edge transition for an edge-triggered
+                    // registration to wake us on later. Leave level-
+                    // triggered so the selector keeps reporting writeable
+                    // until the bucket refills, instead of stalling forever.
+                    throttled = true;
+                    break;
+                }
+                budget = allowed;
+            }
+            let mut iovs = Vec::with_capacity(self.write_chunks.len().min(MAX_IOV));
+            let mut remaining = budget;
+            for (i, chunk) in self.write_chunks.iter().enumerate() {
+                if remaining == 0 || iovs.len() == MAX_IOV {
+                    break;
+                }
+                let start = if i == 0 { self.write_offset } else { 0 };
+                let take = (chunk.len() - start).min(remaining);
+                if take == 0 {
+                    continue;
+                }
+                iovs.push(IoVec::from_slice(&chunk[start..start + take]));
+                remaining -= take;
+            }
+            match writev(self.fd, &iovs) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.advance_transfer(n as u64);
+                    self.consume(n);
+                }
+                Err(Errno::EAGAIN) => {
+                    debug!("write EAGAIN error");
+                    break;
+                }
+                Err(Errno::EINTR) => debug!("write EINTR error"),
+                Err(e) => {
+                    warn!("write error: {}", e);
+                    self.state = self.broken_state();
+                    return;
+                }
+            }
+        }
+        if self.pending_len() == 0 {
+            // A fully flushed download is finished, not interrupted.
+            if self
+                .transfer
+                .as_ref()
+                .map_or(false, |t| t.direction == TransferDirection::Download)
+            {
+                self.end_transfer();
+            }
+            // Stop asking for EPOLLOUT; stay edge-triggered like register_read.
+            event_loop.reregister(self.fd, Interest::readable(true));
+        } else {
+            event_loop.reregister(self.fd, Interest::readable_writable(!throttled));
+        }
     }
+    /// Returns `SockAddr::Inet` or `SockAddr::Unix` depending on listener type.
     pub fn get_peer_address(&self) -> SockAddr {
         let addr = getpeername(self.fd).expect("get peer socket address failed");
         addr
@@ -173,6 +401,7 @@ impl Connection {
                 Ok(n) => {
                     self.read_buf.extend_from_slice(&buf[0..n]);
                     self.state = State::Reading;
+                    self.advance_transfer(n as u64);
                     if n != buf.len() {
                         self.state = State::Finished;
                         debug!("Read data len: {}", n);
@@ -187,11 +416,11 @@ impl Connection {
                 }
             }
             // TODO: buffer replace vec
-            if self.write_buf.len() >= 64 * 1024 {
+            if self.pending_len() >= 64 * 1024 {
                 self.state = State::Reading;
                 debug!("Send data size exceed 64kB");
                 break;
             }
         }
     }
-}
\ No newline at end of file
+}
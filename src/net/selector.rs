@@ -0,0 +1,362 @@
+//! Platform-neutral readiness polling.
+//!
+//! `EventLoop` used to be hardwired to `nix::sys::epoll` and its
+//! `EpollFlags`, which meant `miniftp` only ran on Linux. This module pulls
+//! the polling mechanism out behind a `Selector` trait and a `Readiness`
+//! bitset so `EventLoop`/`Handler`/`Connection` never see an epoll- or
+//! kqueue-specific type: they register interest and receive `Readiness`,
+//! and the platform-specific backend lives entirely in this file.
+
+use crate::net::event_loop::Token;
+use std::io;
+
+bitflags::bitflags! {
+    /// Platform-neutral readiness bits, translated from whatever the
+    /// backing selector (epoll on Linux, kqueue on the BSD/macOS family)
+    /// reports.
+    pub struct Readiness: u8 {
+        const READABLE  = 0b0000_0001;
+        const WRITEABLE = 0b0000_0010;
+        const ERROR     = 0b0000_0100;
+        const HUP       = 0b0000_1000;
+    }
+}
+
+impl Readiness {
+    pub fn is_readable(&self) -> bool {
+        self.contains(Readiness::READABLE)
+    }
+    pub fn is_writeable(&self) -> bool {
+        self.contains(Readiness::WRITEABLE)
+    }
+    pub fn is_error(&self) -> bool {
+        self.contains(Readiness::ERROR)
+    }
+    pub fn is_hup(&self) -> bool {
+        self.contains(Readiness::HUP)
+    }
+    /// A peer-closed condition that carries no more readable data, as
+    /// opposed to a half-close we can still drain by reading.
+    pub fn is_close(&self) -> bool {
+        self.is_hup() && !self.is_readable()
+    }
+}
+
+/// Interest to register for a token: which readiness bits the caller wants
+/// to be woken up for, and whether delivery should be edge-triggered.
+#[derive(Debug, Clone, Copy)]
+pub struct Interest {
+    pub readable: bool,
+    pub writeable: bool,
+    pub edge_triggered: bool,
+}
+
+impl Interest {
+    pub const READABLE: Interest = Interest {
+        readable: true,
+        writeable: false,
+        edge_triggered: false,
+    };
+
+    pub fn readable_writable(edge_triggered: bool) -> Interest {
+        Interest {
+            readable: true,
+            writeable: true,
+            edge_triggered,
+        }
+    }
+
+    pub fn readable(edge_triggered: bool) -> Interest {
+        Interest {
+            readable: true,
+            writeable: false,
+            edge_triggered,
+        }
+    }
+}
+
+/// Backend-agnostic readiness poller. `EventLoop` drives one of these; the
+/// rest of the codebase (`Connection`, `Session`, `FtpServer`) only ever
+/// sees `Token`/`Readiness`.
+pub trait Selector {
+    fn register(&mut self, fd: i32, token: Token, interest: Interest) -> io::Result<()>;
+    fn reregister(&mut self, fd: i32, token: Token, interest: Interest) -> io::Result<()>;
+    fn deregister(&mut self, fd: i32) -> io::Result<()>;
+    /// Block for up to `timeout_ms` (`None` = forever) and return the
+    /// tokens that became ready, together with their readiness bits.
+    fn select(&mut self, timeout_ms: Option<i32>) -> io::Result<Vec<(Token, Readiness)>>;
+}
+
+#[cfg(target_os = "linux")]
+pub use self::epoll::EpollSelector as PlatformSelector;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub use self::kqueue::KqueueSelector as PlatformSelector;
+
+#[cfg(target_os = "linux")]
+mod epoll {
+    use super::{Interest, Readiness, Selector};
+    use crate::net::event_loop::Token;
+    use nix::sys::epoll::{
+        epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+    };
+    use std::collections::HashMap;
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    fn to_epoll_flags(interest: Interest) -> EpollFlags {
+        let mut flags = EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR;
+        if interest.readable {
+            flags |= EpollFlags::EPOLLIN;
+        }
+        if interest.writeable {
+            flags |= EpollFlags::EPOLLOUT;
+        }
+        if interest.edge_triggered {
+            flags |= EpollFlags::EPOLLET;
+        }
+        flags
+    }
+
+    fn to_readiness(flags: EpollFlags) -> Readiness {
+        let mut r = Readiness::empty();
+        if flags.intersects(EpollFlags::EPOLLIN | EpollFlags::EPOLLPRI) {
+            r |= Readiness::READABLE;
+        }
+        if flags.contains(EpollFlags::EPOLLOUT) {
+            r |= Readiness::WRITEABLE;
+        }
+        if flags.contains(EpollFlags::EPOLLERR) {
+            r |= Readiness::ERROR;
+        }
+        if flags.contains(EpollFlags::EPOLLHUP) {
+            r |= Readiness::HUP;
+        }
+        r
+    }
+
+    pub struct EpollSelector {
+        epfd: RawFd,
+        tokens: HashMap<RawFd, Token>,
+    }
+
+    impl EpollSelector {
+        pub fn new() -> io::Result<Self> {
+            let epfd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?;
+            Ok(EpollSelector {
+                epfd,
+                tokens: HashMap::new(),
+            })
+        }
+    }
+
+    impl Selector for EpollSelector {
+        fn register(&mut self, fd: i32, token: Token, interest: Interest) -> io::Result<()> {
+            let mut event = EpollEvent::new(to_epoll_flags(interest), fd as u64);
+            epoll_ctl(self.epfd, EpollOp::EpollCtlAdd, fd, &mut event)?;
+            self.tokens.insert(fd, token);
+            Ok(())
+        }
+        fn reregister(&mut self, fd: i32, token: Token, interest: Interest) -> io::Result<()> {
+            let mut event = EpollEvent::new(to_epoll_flags(interest), fd as u64);
+            epoll_ctl(self.epfd, EpollOp::EpollCtlMod, fd, &mut event)?;
+            self.tokens.insert(fd, token);
+            Ok(())
+        }
+        fn deregister(&mut self, fd: i32) -> io::Result<()> {
+            epoll_ctl(self.epfd, EpollOp::EpollCtlDel, fd, None)?;
+            self.tokens.remove(&fd);
+            Ok(())
+        }
+        fn select(&mut self, timeout_ms: Option<i32>) -> io::Result<Vec<(Token, Readiness)>> {
+            let mut events = vec![EpollEvent::empty(); 1024];
+            let n = epoll_wait(self.epfd, &mut events, timeout_ms.unwrap_or(-1))?;
+            Ok(events[..n]
+                .iter()
+                .filter_map(|e| {
+                    let fd = e.data() as RawFd;
+                    self.tokens.get(&fd).map(|t| (*t, to_readiness(e.events())))
+                })
+                .collect())
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+mod kqueue {
+    use super::{Interest, Readiness, Selector};
+    use crate::net::event_loop::Token;
+    use nix::sys::event::{kevent, kevent_ts, kqueue, EventFilter, EventFlag, FilterFlag, KEvent};
+    use nix::sys::time::TimeSpec;
+    use std::collections::HashMap;
+    use std::io;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    /// One `kevent` per filter a caller is interested in, since kqueue
+    /// tracks read-readiness and write-readiness as separate filters
+    /// rather than one combined bitmask the way epoll does. `armed` is
+    /// which filters are currently registered for `fd`: a filter is only
+    /// ever `EV_DELETE`d if it's armed, since deleting one that was never
+    /// added (e.g. the write filter on a fresh `Interest::READABLE`
+    /// registration) returns `ENOENT` and aborts the whole change batch.
+    fn changes_for(
+        fd: i32,
+        interest: Interest,
+        flags: EventFlag,
+        armed: (bool, bool),
+    ) -> Vec<KEvent> {
+        let mut changes = Vec::with_capacity(2);
+        if interest.readable || armed.0 {
+            changes.push(KEvent::new(
+                fd as usize,
+                EventFilter::EVFILT_READ,
+                if interest.readable {
+                    flags
+                } else {
+                    EventFlag::EV_DELETE
+                },
+                FilterFlag::empty(),
+                0,
+                0,
+            ));
+        }
+        if interest.writeable || armed.1 {
+            changes.push(KEvent::new(
+                fd as usize,
+                EventFilter::EVFILT_WRITE,
+                if interest.writeable {
+                    flags
+                } else {
+                    EventFlag::EV_DELETE
+                },
+                FilterFlag::empty(),
+                0,
+                0,
+            ));
+        }
+        changes
+    }
+
+    pub struct KqueueSelector {
+        kq: RawFd,
+        tokens: HashMap<RawFd, Token>,
+        // Which filters (readable, writeable) are currently armed for a fd,
+        // so changes_for knows which ones are safe to EV_DELETE.
+        armed: HashMap<RawFd, (bool, bool)>,
+    }
+
+    impl KqueueSelector {
+        pub fn new() -> io::Result<Self> {
+            Ok(KqueueSelector {
+                kq: kqueue()?,
+                tokens: HashMap::new(),
+                armed: HashMap::new(),
+            })
+        }
+
+        fn apply(
+            &mut self,
+            fd: i32,
+            token: Token,
+            interest: Interest,
+            add: bool,
+        ) -> io::Result<()> {
+            // EV_CLEAR emulates the edge-triggered behavior requested via
+            // EPOLLET on the epoll backend: the event only fires once per
+            // state transition rather than re-firing while still ready.
+            let mut flags = if add {
+                EventFlag::EV_ADD | EventFlag::EV_ENABLE
+            } else {
+                EventFlag::EV_ADD
+            };
+            if interest.edge_triggered {
+                flags |= EventFlag::EV_CLEAR;
+            }
+            let armed = self.armed.get(&fd).copied().unwrap_or((false, false));
+            let changes = changes_for(fd, interest, flags, armed);
+            kevent(self.kq, &changes, &mut [], 0)?;
+            self.tokens.insert(fd, token);
+            self.armed
+                .insert(fd, (interest.readable, interest.writeable));
+            Ok(())
+        }
+    }
+
+    impl Selector for KqueueSelector {
+        fn register(&mut self, fd: i32, token: Token, interest: Interest) -> io::Result<()> {
+            self.apply(fd, token, interest, true)
+        }
+        fn reregister(&mut self, fd: i32, token: Token, interest: Interest) -> io::Result<()> {
+            self.apply(fd, token, interest, false)
+        }
+        fn deregister(&mut self, fd: i32) -> io::Result<()> {
+            let armed = self.armed.remove(&fd).unwrap_or((false, false));
+            let delete = Interest {
+                readable: false,
+                writeable: false,
+                edge_triggered: false,
+            };
+            let changes = changes_for(fd, delete, EventFlag::EV_DELETE, armed);
+            kevent(self.kq, &changes, &mut [], 0)?;
+            self.tokens.remove(&fd);
+            Ok(())
+        }
+        fn select(&mut self, timeout_ms: Option<i32>) -> io::Result<Vec<(Token, Readiness)>> {
+            let mut events = vec![
+                KEvent::new(
+                    0,
+                    EventFilter::EVFILT_READ,
+                    EventFlag::empty(),
+                    FilterFlag::empty(),
+                    0,
+                    0,
+                );
+                1024
+            ];
+            // `None` blocks forever via `kevent_ts`'s own `Option<TimeSpec>`,
+            // rather than trying to fake infinity with a sentinel timeout.
+            let timeout =
+                timeout_ms.map(|ms| TimeSpec::from(Duration::from_millis(ms.max(0) as u64)));
+            let n = kevent_ts(self.kq, &[], &mut events, timeout)?;
+            let mut out = Vec::with_capacity(n);
+            for e in &events[..n] {
+                let fd = e.ident() as RawFd;
+                let token = match self.tokens.get(&fd) {
+                    Some(t) => *t,
+                    None => continue,
+                };
+                let mut r = Readiness::empty();
+                match e.filter() {
+                    Ok(EventFilter::EVFILT_READ) => r |= Readiness::READABLE,
+                    Ok(EventFilter::EVFILT_WRITE) => r |= Readiness::WRITEABLE,
+                    _ => (),
+                }
+                // EOF/error surface through fflags/EV_EOF rather than a
+                // distinct filter, mirroring EPOLLHUP/EPOLLERR.
+                if e.flags().contains(EventFlag::EV_EOF) {
+                    r |= Readiness::HUP;
+                }
+                if !e.fflags().is_empty() {
+                    r |= Readiness::ERROR;
+                }
+                out.push((token, r));
+            }
+            Ok(out)
+        }
+    }
+}
@@ -2,19 +2,22 @@ use crate::handler::session::Session;
 use crate::net::connection::Connection;
 use crate::net::event_loop::{EventLoop, Handler, Token};
 use crate::net::queue::{BlockingQueue, BlockingQueueRef};
+use crate::net::selector::{Interest, Readiness};
 use crate::net::sorted_list::TimerList;
 use crate::threadpool::threadpool::ThreadPool;
 use crate::utils::config::{Config, DEFAULT_CONF_FILE};
 use crate::utils::utils::already_running;
 use log::{debug, info, warn};
-use nix::sys::epoll::EpollFlags;
+use nix::unistd::{getpid, read};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::env;
 use std::os::unix::prelude::AsRawFd;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
-const DEFAULT_TIME_OUT: u64 = 60; // time (s)
+/// First fd passed by a socket-activating supervisor (`SD_LISTEN_FDS_START`).
+const SD_LISTEN_FDS_START: i32 = 3;
 
 type TaskQueueRef = BlockingQueueRef<Arc<Mutex<Session>>>;
 
@@ -26,6 +29,7 @@ pub struct FtpServer {
     event_loop: EventLoop,
     conn_map: Arc<Mutex<HashMap<i32, i32>>>, // <cmd_fd, data fd>
     config: Config,
+    unix_listen_fd: Option<i32>, // local/administrative control channel, see Config::unix_socket_path
 }
 
 impl FtpServer {
@@ -40,14 +44,33 @@ impl FtpServer {
                 session.lock().unwrap().handle_command();
             });
         }
+
+        let unix_listen_fd = config.unix_socket_path.as_ref().map(|path| {
+            let (fd, listener) = Connection::bind_unix(path);
+            // Leak the fd so the event loop keeps owning it, not `UnixListener`.
+            std::mem::forget(listener);
+            event_loop.register(fd, Token::Listen(fd), Interest::READABLE);
+            info!("Listening on unix socket {}, fd: {}", path, fd);
+            fd
+        });
+
         FtpServer {
-            conn_list: TimerList::new(DEFAULT_TIME_OUT),
+            conn_list: TimerList::new(config.idle_timeout_secs),
             request_queue: q,
             worker_pool: pool,
             sessions: HashMap::new(),
             event_loop: event_loop.clone(),
             conn_map: Arc::new(Mutex::new(HashMap::new())),
             config,
+            unix_listen_fd,
+        }
+    }
+}
+
+impl Drop for FtpServer {
+    fn drop(&mut self) {
+        if let Some(path) = self.config.unix_socket_path.as_ref() {
+            Connection::unlink_unix(path);
         }
     }
 }
@@ -55,14 +78,29 @@ impl FtpServer {
 impl Handler for FtpServer {
     type Message = String;
     type Timeout = i32;
-    fn ready(&mut self, event_loop: &mut EventLoop, token: Token, revent: EpollFlags) {
+    fn ready(&mut self, event_loop: &mut EventLoop, token: Token, revent: Readiness) {
         if let Token::Listen(listen_fd) = token {
-            let mut conn = Connection::accept(listen_fd);
+            let mut conn = if self.unix_listen_fd == Some(listen_fd) {
+                Connection::accept_unix(listen_fd)
+            } else {
+                Connection::accept(listen_fd)
+            };
             let fd = conn.get_fd();
             debug!("A new connection: {:?}:{}", token, fd);
 
             if self.config.max_clients > self.sessions.len() {
                 conn.register_read(event_loop);
+                // Rate limiting applies to data connections, not this one.
+                // `self.config.max_rate_bytes_per_sec` reaches `Session`
+                // via the `&self.config` passed into `Session::new` below;
+                // TODO: no commit in this series actually calls
+                // `Connection::set_rate_limit` on a data connection yet, so
+                // until that lands in Session, max_rate_bytes_per_sec has
+                // no effect on real transfers.
+                // Tracked separately so the reaper can deregister/shutdown
+                // the cmd_fd without a handle into the session itself.
+                self.conn_list
+                    .insert(fd, Rc::new(RefCell::new(Connection::new(fd))));
                 let s = Session::new(&self.config, conn, event_loop, &self.conn_map);
                 self.sessions.insert(fd, Arc::new(Mutex::new(s)));
             } else {
@@ -75,29 +113,58 @@ impl Handler for FtpServer {
             }
         }
     }
-    fn notify(&mut self, _event_loop: &mut EventLoop, token: Token, revents: EpollFlags) {
+    fn notify(&mut self, event_loop: &mut EventLoop, token: Token, revents: Readiness) {
         if let Token::Notify(fd) = token {
+            // Command/data activity on this session: bump it to the tail
+            // of the idle list so the reaper leaves it alone.
+            self.conn_list.touch(fd);
             let s = self.sessions.get(&fd).unwrap();
             self.request_queue.push_back(s.clone());
             // TODO: Session 注销逻辑
         } else if let Token::Timer(fd) = token {
-            debug!("timer: {}", fd);
-            // TODO: 应该定时注销的是 session, 注销一些最不活跃的session
-            // let old_len = self.conn_list.len();
-            // self.conn_list.remove_idle();
-            // let mut _buf = [0u8; 8];
-            // read(fd, &mut _buf).unwrap_or_default(); // 读取这个 timer_fd
-            // let new_len = self.conn_list.len();
-            // if old_len != new_len {
-            //     debug!(
-            //         "Remove idle connection, old len:{}, new len: {}",
-            //         old_len, new_len
-            //     );
-            // }
+            let mut _buf = [0u8; 8];
+            read(fd, &mut _buf).unwrap_or_default(); // drain the timerfd's 8-byte expiration counter
+
+            let old_len = self.conn_list.len();
+            let expired = self.conn_list.remove_idle();
+            let new_len = self.conn_list.len();
+            if old_len != new_len {
+                debug!(
+                    "Remove idle connection, old len:{}, new len: {}",
+                    old_len, new_len
+                );
+            }
+            for (cmd_fd, conn) in expired {
+                // NOTE: holding a resumable transfer's session open here
+                // during RESYNC_GRACE_PERIOD needs Session to mirror its
+                // data connection's State/TransferProgress into this
+                // (currently disconnected) bookkeeping entry; until that
+                // wiring lands, every idle session is reaped outright.
+                if let Some(data_fd) = self.conn_map.lock().unwrap().remove(&cmd_fd) {
+                    event_loop.deregister(data_fd);
+                }
+                conn.borrow().deregister(event_loop);
+                self.sessions.remove(&cmd_fd);
+                info!("Reaped idle session, cmd_fd: {}", cmd_fd);
+            }
         }
     }
 }
 
+/// If we were started by a socket-activating supervisor (systemd,
+/// inetd-style), return the fd of the listening socket it bound for us.
+fn listen_fd_from_activation() -> Option<i32> {
+    let listen_pid: i32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if listen_pid != getpid().as_raw() {
+        return None;
+    }
+    let listen_fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if listen_fds < 1 {
+        return None;
+    }
+    Some(SD_LISTEN_FDS_START)
+}
+
 pub fn run_server() {
     if already_running() {
         warn!("Already running...");
@@ -106,13 +173,20 @@ pub fn run_server() {
 
     let config = Config::new(DEFAULT_CONF_FILE);
     debug!("config: {:?}", config);
-    let addr = format!("{}:{}", config.server_addr, config.server_port);
-    let (_, listener) = Connection::bind(&addr);
-    info!(
-        "Start server listener, addr: {}, fd: {:?}",
-        addr,
-        listener.as_raw_fd()
-    );
+
+    let (_, listener) = match listen_fd_from_activation() {
+        Some(fd) => {
+            info!("Inherited listening socket from supervisor, fd: {}", fd);
+            Connection::from_listen_fd(fd)
+        }
+        None => {
+            let addr = format!("{}:{}", config.server_addr, config.server_port);
+            let (fd, listener) = Connection::bind(&addr);
+            info!("Start server listener, addr: {}, fd: {:?}", addr, fd);
+            (fd, listener)
+        }
+    };
+    info!("listener fd: {:?}", listener.as_raw_fd());
 
     let mut event_loop = EventLoop::new(listener);
     let mut ftpserver = FtpServer::new(config, &mut event_loop);